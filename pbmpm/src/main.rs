@@ -1,7 +1,10 @@
 use bevy::prelude::*;
-use glam::Vec2;
+use glam::{Mat2, Vec2};
+use noise::{NoiseFn, Simplex};
+use rand::Rng;
+use serde::Deserialize;
 use std::collections::HashMap;
-use std::f32::consts::PI;
+use std::f32::consts::{PI, TAU};
 
 fn main() {
     App::new()
@@ -10,8 +13,37 @@ fn main() {
         .insert_resource(BounceDampening(0.8))
         .insert_resource(WindowSize { width: 400.0, height: 300.0 })
         .insert_resource(Grid::new(20.0))
-        .add_systems(Startup, setup)
-        .add_systems(Update, (update_window_size, update_particles, update_grid, resolve_collisions))
+        .insert_resource(Flocking {
+            enabled: false,
+            perception_radius: 40.0,
+            separation_radius: 15.0,
+            alignment_weight: 1.0,
+            cohesion_weight: 0.8,
+            separation_weight: 1.5,
+        })
+        .insert_resource(NBodyGravity {
+            g: 50.0,
+            softening: 2.0,
+            theta: 0.5,
+        })
+        .insert_resource(Colliders(vec![
+            Box::new(NoisePolygonCollider::new(150.0, 20.0, 1)),
+            Box::new(Obstacle(CircleCollider { radius: 25.0 })),
+        ]))
+        .add_systems(Startup, (load_effect_library, setup))
+        .add_systems(
+            Update,
+            (
+                update_window_size,
+                spawn_from_emitters,
+                despawn_expired,
+                apply_nbody_gravity,
+                update_grid,
+                apply_flocking,
+                grid_to_particles,
+                resolve_collisions,
+            ),
+        )
         .run();
 }
 
@@ -27,23 +59,276 @@ struct WindowSize {
     height: f32,
 }
 
+/// A static obstacle or boundary, described by its signed distance field: positive in free
+/// space, negative once the query point is inside the solid.
+trait Collider: Send + Sync {
+    fn distance(&self, point: Vec2) -> f32;
+
+    /// Numeric gradient of the SDF, pointing toward increasing distance (out of the solid).
+    /// Shared by every shape so implementers only need to provide `distance`.
+    fn normal(&self, point: Vec2) -> Vec2 {
+        let eps = 0.5;
+        let dx = self.distance(point + Vec2::X * eps) - self.distance(point - Vec2::X * eps);
+        let dy = self.distance(point + Vec2::Y * eps) - self.distance(point - Vec2::Y * eps);
+        Vec2::new(dx, dy).normalize_or_zero()
+    }
+}
+
+/// Keeps particles inside an axis-aligned rectangle centered at the origin.
+struct RectCollider {
+    half_extents: Vec2,
+}
+
+impl Collider for RectCollider {
+    fn distance(&self, point: Vec2) -> f32 {
+        let d = point.abs() - self.half_extents;
+        -(d.max(Vec2::ZERO).length() + d.x.max(d.y).min(0.0))
+    }
+}
+
+/// Keeps particles inside a disc centered at the origin.
+struct CircleCollider {
+    radius: f32,
+}
+
+impl Collider for CircleCollider {
+    fn distance(&self, point: Vec2) -> f32 {
+        self.radius - point.length()
+    }
+}
+
+/// A deformable "planet" outline: a circle whose radius is perturbed per-angle by 2D simplex
+/// noise, sampled around the unit circle so the outline stays closed and seamless.
+struct NoisePolygonCollider {
+    base_radius: f32,
+    amplitude: f32,
+    noise: Simplex,
+}
+
+impl NoisePolygonCollider {
+    fn new(base_radius: f32, amplitude: f32, seed: u32) -> Self {
+        Self {
+            base_radius,
+            amplitude,
+            noise: Simplex::new(seed),
+        }
+    }
+}
+
+impl Collider for NoisePolygonCollider {
+    fn distance(&self, point: Vec2) -> f32 {
+        let angle = point.y.atan2(point.x);
+        let sample = self.noise.get([angle.cos() as f64, angle.sin() as f64]) as f32;
+        let radius = self.base_radius + sample * self.amplitude;
+        radius - point.length()
+    }
+}
+
+/// Inverts a collider's sign convention, turning a "stay inside" boundary into a "stay outside"
+/// obstacle — e.g. a `CircleCollider` normally keeps particles in a disc, but
+/// `Obstacle(CircleCollider { .. })` keeps them out of one.
+struct Obstacle<C: Collider>(C);
+
+impl<C: Collider> Collider for Obstacle<C> {
+    fn distance(&self, point: Vec2) -> f32 {
+        -self.0.distance(point)
+    }
+}
+
+/// Extra colliders layered on top of the window bounds (terrain, obstacles), so a scene isn't
+/// limited to the single window-rectangle boundary.
+#[derive(Resource, Default)]
+struct Colliders(Vec<Box<dyn Collider>>);
+
 #[derive(Component)]
 struct Particle {
     velocity: Vec2,
+    mass: f32,
+    /// Affine velocity field (APIC) used to carry angular momentum between grid and particle.
+    c: Mat2,
+}
+
+// Shared by `Grid` and the collision broadphase so both spatial hashes bin positions the same way.
+fn world_to_cell(position: Vec2, cell_size: f32) -> (i32, i32) {
+    ((position.x / cell_size).floor() as i32, (position.y / cell_size).floor() as i32)
 }
 
 #[derive(Resource)]
 struct Grid {
     cell_size: f32,
     cells: HashMap<(i32, i32), GridCell>,
-    previous_velocities: HashMap<(i32, i32), Vec2>,
 }
 
 
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone)]
 struct GridCell {
     velocity: Vec2,
     mass: f32,
+    /// Particles whose home cell (not splat stencil) is this one, for neighbor lookups.
+    entities: Vec<Entity>,
+}
+
+#[derive(Resource)]
+struct NBodyGravity {
+    g: f32,
+    softening: f32,
+    theta: f32,
+}
+
+/// A single node of a Barnes-Hut quadtree: the total mass and center of mass of every body
+/// beneath it, plus either a leaf body or four child quadrants.
+struct QuadNode {
+    center: Vec2,
+    half_size: f32,
+    mass: f32,
+    center_of_mass: Vec2,
+    body: Option<(Vec2, f32)>,
+    children: Option<Box<[QuadNode; 4]>>,
+}
+
+impl QuadNode {
+    fn new_leaf(center: Vec2, half_size: f32) -> Self {
+        Self {
+            center,
+            half_size,
+            mass: 0.0,
+            center_of_mass: Vec2::ZERO,
+            body: None,
+            children: None,
+        }
+    }
+
+    fn child_index(&self, position: Vec2) -> usize {
+        match (position.x >= self.center.x, position.y >= self.center.y) {
+            (false, false) => 0,
+            (true, false) => 1,
+            (false, true) => 2,
+            (true, true) => 3,
+        }
+    }
+
+    fn child_center(&self, index: usize) -> Vec2 {
+        let quarter = self.half_size * 0.5;
+        let offset = match index {
+            0 => Vec2::new(-quarter, -quarter),
+            1 => Vec2::new(quarter, -quarter),
+            2 => Vec2::new(-quarter, quarter),
+            _ => Vec2::new(quarter, quarter),
+        };
+        self.center + offset
+    }
+
+    fn insert(&mut self, position: Vec2, mass: f32) {
+        if self.mass <= 0.0 && self.children.is_none() {
+            self.body = Some((position, mass));
+            self.mass = mass;
+            self.center_of_mass = position;
+            return;
+        }
+
+        if self.children.is_none() {
+            // Too small to keep subdividing (e.g. coincident bodies): merge instead of recursing forever.
+            if self.half_size < 1e-3 {
+                self.center_of_mass = (self.center_of_mass * self.mass + position * mass) / (self.mass + mass);
+                self.mass += mass;
+                return;
+            }
+
+            let children = std::array::from_fn(|i| QuadNode::new_leaf(self.child_center(i), self.half_size * 0.5));
+            self.children = Some(Box::new(children));
+
+            let (existing_position, existing_mass) = self.body.take().unwrap();
+            let existing_idx = self.child_index(existing_position);
+            self.children.as_mut().unwrap()[existing_idx].insert(existing_position, existing_mass);
+        }
+
+        let idx = self.child_index(position);
+        self.children.as_mut().unwrap()[idx].insert(position, mass);
+
+        self.center_of_mass = (self.center_of_mass * self.mass + position * mass) / (self.mass + mass);
+        self.mass += mass;
+    }
+
+    /// Gravitational acceleration this node's mass exerts at `position`, recursing into
+    /// children only when the node isn't a good enough far-field approximation (`s/d >= theta`).
+    fn acceleration_at(&self, position: Vec2, gravity: &NBodyGravity) -> Vec2 {
+        if self.mass <= 0.0 {
+            return Vec2::ZERO;
+        }
+
+        let offset = self.center_of_mass - position;
+        let distance = offset.length();
+
+        let treat_as_point = match &self.children {
+            None => true,
+            Some(_) => {
+                let width = self.half_size * 2.0;
+                distance > f32::EPSILON && width / distance < gravity.theta
+            }
+        };
+
+        if treat_as_point {
+            let denom = (distance * distance + gravity.softening * gravity.softening).powf(1.5);
+            if denom <= f32::EPSILON {
+                return Vec2::ZERO;
+            }
+            return gravity.g * self.mass * offset / denom;
+        }
+
+        self.children
+            .as_ref()
+            .unwrap()
+            .iter()
+            .fold(Vec2::ZERO, |total, child| total + child.acceleration_at(position, gravity))
+    }
+}
+
+/// A spawnable particle preset, declared in `assets/effects.toml` and looked up by name.
+#[derive(Deserialize, Clone)]
+struct EffectTemplate {
+    name: String,
+    color: [f32; 4],
+    size: f32,
+    lifetime: f32,
+    /// Add the emitting particle's velocity (zero if the emitter is not a particle) to each new
+    /// particle's launch velocity.
+    inherit_velocity: bool,
+}
+
+#[derive(Deserialize)]
+struct EffectLibraryFile {
+    effects: Vec<EffectTemplate>,
+}
+
+#[derive(Resource, Default)]
+struct EffectLibrary {
+    effects: HashMap<String, EffectTemplate>,
+}
+
+#[derive(Component)]
+struct Emitter {
+    effect: String,
+    spawn_rate: f32,
+    spawn_radius: f32,
+    initial_speed: f32,
+    /// Half-angle of the spawn cone in radians, measured from +Y; use PI for a full circle.
+    spawn_cone: f32,
+    accumulator: f32,
+}
+
+#[derive(Component)]
+struct Lifetime {
+    remaining: f32,
+}
+
+#[derive(Resource)]
+struct Flocking {
+    enabled: bool,
+    perception_radius: f32,
+    separation_radius: f32,
+    alignment_weight: f32,
+    cohesion_weight: f32,
+    separation_weight: f32,
 }
 
 impl Grid {
@@ -51,23 +336,19 @@ impl Grid {
         Self {
             cell_size,
             cells: HashMap::new(),
-            previous_velocities: HashMap::new(),
         }
     }
 
     fn clear(&mut self) {
-        self.previous_velocities.clear(); // Reset previous velocities
-        for (cell_idx, cell) in &self.cells {
-            self.previous_velocities.insert(*cell_idx, cell.velocity); // Store last frame's velocity
-        }
         self.cells.clear(); // Reset grid
     }
 
     fn world_to_cell(&self, position: Vec2) -> (i32, i32) {
-        (
-            (position.x / self.cell_size) as i32,
-            (position.y / self.cell_size) as i32,
-        )
+        world_to_cell(position, self.cell_size)
+    }
+
+    fn cell_center(&self, cell_idx: (i32, i32)) -> Vec2 {
+        Vec2::new(cell_idx.0 as f32 + 0.5, cell_idx.1 as f32 + 0.5) * self.cell_size
     }
 }
 
@@ -75,26 +356,100 @@ impl Grid {
 fn setup(mut commands: Commands) {
     commands.spawn(Camera2d);
 
-    let num_particles = 10;
-    let spawn_radius = 50.0;
+    commands.spawn((
+        Emitter {
+            effect: "spark".to_string(),
+            spawn_rate: 5.0,
+            spawn_radius: 10.0,
+            initial_speed: 60.0,
+            spawn_cone: PI,
+            accumulator: 0.0,
+        },
+        Transform::from_translation(Vec3::ZERO),
+    ));
+}
+
+fn load_effect_library(mut commands: Commands) {
+    let contents = std::fs::read_to_string("assets/effects.toml")
+        .expect("failed to read assets/effects.toml");
+    let file: EffectLibraryFile =
+        toml::from_str(&contents).expect("failed to parse assets/effects.toml");
+
+    let effects = file.effects.into_iter().map(|effect| (effect.name.clone(), effect)).collect();
+    commands.insert_resource(EffectLibrary { effects });
+}
+
+// Spawns particles from each `Emitter` at its configured rate, looking up appearance and
+// lifetime from the named entry in `EffectLibrary` rather than hard-coding them per call site.
+fn spawn_from_emitters(
+    mut commands: Commands,
+    time: Res<Time>,
+    effects: Res<EffectLibrary>,
+    mut emitters: Query<(&Transform, &mut Emitter, Option<&Particle>)>,
+) {
+    let delta_time = time.delta_secs();
+    let mut rng = rand::thread_rng();
 
-    for i in 0..num_particles {
-        let angle = (i as f32 / num_particles as f32) * 2.0 * PI;
-        let position = Vec2::new(spawn_radius * angle.cos(), spawn_radius * angle.sin());
-        let initial_velocity = Vec2::new((i as f32 - 5.0) * 5.0, 50.0);
+    for (transform, mut emitter, source) in &mut emitters {
+        let Some(effect) = effects.effects.get(&emitter.effect) else {
+            continue;
+        };
+
+        emitter.accumulator += emitter.spawn_rate * delta_time;
+
+        while emitter.accumulator >= 1.0 {
+            emitter.accumulator -= 1.0;
+
+            // `gen_range` panics on an empty range, which a straight jet (spawn_cone: 0.0) or a
+            // point emitter (spawn_radius: 0.0) would otherwise hit.
+            let direction_angle = if emitter.spawn_cone > 0.0 {
+                rng.gen_range(-emitter.spawn_cone..emitter.spawn_cone)
+            } else {
+                0.0
+            };
+            let direction = Vec2::new(direction_angle.sin(), direction_angle.cos());
+
+            let spawn_angle = rng.gen_range(0.0..TAU);
+            let spawn_distance = if emitter.spawn_radius > 0.0 {
+                rng.gen_range(0.0..emitter.spawn_radius)
+            } else {
+                0.0
+            };
+            let spawn_offset = Vec2::new(spawn_angle.cos(), spawn_angle.sin()) * spawn_distance;
+
+            let inherited_velocity = if effect.inherit_velocity {
+                source.map(|particle| particle.velocity).unwrap_or(Vec2::ZERO)
+            } else {
+                Vec2::ZERO
+            };
+
+            commands.spawn((
+                Particle {
+                    velocity: inherited_velocity + direction * emitter.initial_speed,
+                    mass: 1.0,
+                    c: Mat2::ZERO,
+                },
+                Lifetime { remaining: effect.lifetime },
+                Sprite {
+                    color: Color::srgba(effect.color[0], effect.color[1], effect.color[2], effect.color[3]),
+                    custom_size: Some(Vec2::splat(effect.size)),
+                    ..Default::default()
+                },
+                Transform::from_translation((transform.translation.xy() + spawn_offset).extend(0.0)),
+                Visibility::Visible,
+            ));
+        }
+    }
+}
+
+fn despawn_expired(mut commands: Commands, time: Res<Time>, mut query: Query<(Entity, &mut Lifetime)>) {
+    let delta_time = time.delta_secs();
 
-        commands.spawn((
-            Particle {
-                velocity: initial_velocity,
-            },
-            Sprite {
-                color: Color::WHITE,
-                custom_size: Some(Vec2::splat(5.0)),
-                ..Default::default()
-            },
-            Transform::from_translation(Vec3::new(position.x, position.y, 0.0)),
-            Visibility::Visible,
-        ));
+    for (entity, mut lifetime) in &mut query {
+        lifetime.remaining -= delta_time;
+        if lifetime.remaining <= 0.0 {
+            commands.entity(entity).despawn();
+        }
     }
 }
 
@@ -108,152 +463,468 @@ fn update_window_size(
     }
 }
 
-fn update_particles(
-    mut query: Query<(&mut Particle, &mut Transform, &Sprite)>,
-    window_size: Res<WindowSize>,
+// Quadratic B-spline weights for the particle's 3x3 neighborhood, shared by P2G and G2P
+// so both passes sample the same stencil.
+fn quadratic_weights(cell_offset: Vec2) -> [Vec2; 3] {
+    [
+        0.5 * (0.5 - cell_offset) * (0.5 - cell_offset),
+        0.75 - cell_offset * cell_offset,
+        0.5 * (0.5 + cell_offset) * (0.5 + cell_offset),
+    ]
+}
+
+// Particle-to-grid: scatter APIC momentum onto the grid, then solve each cell's velocity.
+fn update_grid(
+    mut grid: ResMut<Grid>,
     gravity: Res<Gravity>,
     bounce_dampening: Res<BounceDampening>,
+    window_size: Res<WindowSize>,
+    colliders: Res<Colliders>,
     time: Res<Time>,
+    query: Query<(Entity, &Transform, &Particle)>,
 ) {
-    let delta_time = time.delta_secs();
-
-    for (mut particle, mut transform, sprite) in &mut query {
-        particle.velocity += gravity.0 * delta_time;
+    grid.clear();
 
-        let particle_size = sprite.custom_size.unwrap_or(Vec2::new(5.0, 5.0));
-        let half_size_x = particle_size.x / 2.0;
-        let half_size_y = particle_size.y / 2.0;
+    for (entity, transform, particle) in &query {
+        let world_pos = transform.translation.xy();
+        let cell_idx = grid.world_to_cell(world_pos);
+        // Offset from the home cell's *center* node, not its corner, so it stays in [-0.5, 0.5)
+        // as the quadratic B-spline weights below require.
+        let cell_offset = world_pos / grid.cell_size
+            - Vec2::new(cell_idx.0 as f32 + 0.5, cell_idx.1 as f32 + 0.5);
+        let weights = quadratic_weights(cell_offset);
 
-        let mut new_position = transform.translation.xy() + particle.velocity * delta_time;
+        // Bucket the particle under its own home cell so spatial queries (flocking, broadphase)
+        // can reuse this grid instead of scanning every particle.
+        grid.cells.entry(cell_idx).or_insert(GridCell::default()).entities.push(entity);
 
-        let bounds_x = window_size.width - half_size_x;
-        let bounds_y = window_size.height - half_size_y;
+        for gx in 0..3 {
+            for gy in 0..3 {
+                let weight = weights[gx].x * weights[gy].y;
+                let neighbor_cell = (cell_idx.0 + gx as i32 - 1, cell_idx.1 + gy as i32 - 1);
+                let node_pos = grid.cell_center(neighbor_cell);
+                let cell = grid.cells.entry(neighbor_cell).or_insert(GridCell::default());
 
-        if new_position.x.abs() > bounds_x {
-            new_position.x = bounds_x * new_position.x.signum();
-            particle.velocity.x *= -bounce_dampening.0;
+                // APIC momentum scatter: particle velocity plus the affine correction
+                // extrapolated out to this node.
+                let affine_velocity = particle.velocity + particle.c * (node_pos - world_pos);
+                cell.mass += weight * particle.mass;
+                cell.velocity += weight * particle.mass * affine_velocity;
+            }
         }
+    }
 
-        if new_position.y.abs() > bounds_y {
-            new_position.y = bounds_y * new_position.y.signum();
-            particle.velocity.y *= -bounce_dampening.0;
+    let delta_time = time.delta_secs();
 
-            if particle.velocity.y.abs() < 0.1 {
-                particle.velocity.y = 0.0;
-            }
+    // The window itself is always a collider; scenes can layer additional obstacles or terrain
+    // (e.g. a noisy floor) on top via the `Colliders` resource instead of assuming it's the only one.
+    let window_collider = RectCollider {
+        half_extents: Vec2::new(window_size.width, window_size.height),
+    };
+    let active_colliders = std::iter::once(&window_collider as &dyn Collider)
+        .chain(colliders.0.iter().map(|collider| collider.as_ref()));
+    let active_colliders: Vec<&dyn Collider> = active_colliders.collect();
+
+    for (&cell_idx, cell) in grid.cells.iter_mut() {
+        if cell.mass <= 0.0 {
+            continue;
         }
 
-        let max_velocity = window_size.width.max(window_size.height) * 2.0;
-        particle.velocity = particle.velocity.clamp_length_max(max_velocity);
+        cell.velocity /= cell.mass; // Momentum -> velocity
+        cell.velocity += gravity.0 * delta_time;
+
+        // Enforce boundary conditions on cells that have crossed into a collider's solid region
+        let node_pos = Vec2::new(cell_idx.0 as f32 + 0.5, cell_idx.1 as f32 + 0.5) * grid.cell_size;
+        for collider in &active_colliders {
+            let distance = collider.distance(node_pos);
+            if distance >= 0.0 {
+                continue;
+            }
 
-        transform.translation = new_position.extend(0.0);
+            let normal = collider.normal(node_pos);
+            let normal_velocity = cell.velocity.dot(normal);
+            if normal_velocity < 0.0 {
+                cell.velocity -= normal * normal_velocity * (1.0 + bounce_dampening.0);
+            }
+        }
     }
 }
 
-fn update_grid(
-    mut grid: ResMut<Grid>,
-    gravity: Res<Gravity>,
-    query: Query<(&Transform, &Particle)>,
+// Grid-to-particle: gather velocity and the affine field back from the grid, advect, then push
+// each particle back out of any collider it penetrated (the grid's own boundary handling only
+// nudges cell velocities, which is too coarse to stop a fast particle from tunneling for a frame
+// or two — this is the actual per-particle contact response the MPM path runs).
+fn grid_to_particles(
+    grid: Res<Grid>,
+    flocking: Res<Flocking>,
+    window_size: Res<WindowSize>,
+    colliders: Res<Colliders>,
+    bounce_dampening: Res<BounceDampening>,
+    time: Res<Time>,
+    mut query: Query<(&mut Particle, &mut Transform, &Sprite)>,
 ) {
-    let mut previous_velocities = HashMap::new();
-    for (cell_idx, cell) in &grid.cells {
-        previous_velocities.insert(*cell_idx, cell.velocity);
+    if flocking.enabled {
+        return;
     }
 
-    grid.clear();
+    let delta_time = time.delta_secs();
+    let inv_cell_size_sq = 4.0 / (grid.cell_size * grid.cell_size);
+
+    let window_collider = RectCollider {
+        half_extents: Vec2::new(window_size.width, window_size.height),
+    };
+    let active_colliders: Vec<&dyn Collider> = std::iter::once(&window_collider as &dyn Collider)
+        .chain(colliders.0.iter().map(|collider| collider.as_ref()))
+        .collect();
 
-    for (transform, particle) in &query {
+    for (mut particle, mut transform, sprite) in &mut query {
         let world_pos = transform.translation.xy();
         let cell_idx = grid.world_to_cell(world_pos);
-        let cell_offset = world_pos / grid.cell_size - Vec2::new(cell_idx.0 as f32, cell_idx.1 as f32);
+        // Same center-node offset as the P2G pass in `update_grid` — keeping both in sync is
+        // what makes the weights a non-negative partition of unity.
+        let cell_offset = world_pos / grid.cell_size
+            - Vec2::new(cell_idx.0 as f32 + 0.5, cell_idx.1 as f32 + 0.5);
+        let weights = quadratic_weights(cell_offset);
 
-        let weights = [
-            0.5 * (0.5 - cell_offset) * (0.5 - cell_offset),
-            0.75 - cell_offset * cell_offset,
-            0.5 * (0.5 + cell_offset) * (0.5 + cell_offset),
-        ];
+        let mut velocity = Vec2::ZERO;
+        let mut c = Mat2::ZERO;
 
         for gx in 0..3 {
             for gy in 0..3 {
                 let weight = weights[gx].x * weights[gy].y;
                 let neighbor_cell = (cell_idx.0 + gx as i32 - 1, cell_idx.1 + gy as i32 - 1);
-                let cell = grid.cells.entry(neighbor_cell).or_insert(GridCell::default());
+                let Some(cell) = grid.cells.get(&neighbor_cell) else {
+                    continue;
+                };
+                // A cell that never accumulated any mass never had its momentum normalized into
+                // a velocity in `update_grid` — treat it as empty rather than gathering raw
+                // momentum as if it were velocity.
+                if cell.mass <= 0.0 {
+                    continue;
+                }
+
+                let node_pos = grid.cell_center(neighbor_cell);
+                let dist = node_pos - world_pos;
+                velocity += weight * cell.velocity;
+                c += weight * inv_cell_size_sq * Mat2::from_cols(cell.velocity * dist.x, cell.velocity * dist.y);
+            }
+        }
+
+        particle.velocity = velocity;
+        particle.c = c;
+
+        let particle_radius = sprite.custom_size.unwrap_or(Vec2::splat(5.0)).x / 2.0;
+        let mut position = world_pos + delta_time * velocity;
 
-                // Use mass-weighted velocity updates (momentum conservation)
-                cell.mass += weight * particle.velocity.length();
-                cell.velocity += weight * particle.velocity;
+        for collider in &active_colliders {
+            let distance = collider.distance(position);
+            if distance >= particle_radius {
+                continue;
+            }
+
+            let normal = collider.normal(position);
+            position += normal * (particle_radius - distance);
+
+            let normal_velocity = particle.velocity.dot(normal);
+            if normal_velocity < 0.0 {
+                particle.velocity -= normal * normal_velocity * (1.0 + bounce_dampening.0);
             }
         }
+
+        transform.translation = position.extend(0.0);
     }
-    
-    for (cell_idx, cell) in grid.cells.iter_mut() {
-        if cell.mass > 0.0 {
-            let prev_velocity = previous_velocities.get(cell_idx).copied().unwrap_or(Vec2::ZERO);
-            cell.velocity = (cell.velocity + prev_velocity) * 0.5; // Simple velocity smoothing
-            cell.velocity += gravity.0; // Apply gravity
-        }
+}
+
+
+
+
+// Inter-particle gravity via a Barnes-Hut quadtree, rebuilt fresh each frame from the current
+// positions, so attraction stays O(n log n) instead of an O(n^2) pairwise sum.
+fn apply_nbody_gravity(
+    gravity: Res<NBodyGravity>,
+    time: Res<Time>,
+    mut query: Query<(&Transform, &mut Particle)>,
+) {
+    let delta_time = time.delta_secs();
+
+    let bodies: Vec<(Vec2, f32)> = query
+        .iter()
+        .map(|(transform, particle)| (transform.translation.xy(), particle.mass))
+        .collect();
+
+    let Some(&(first, _)) = bodies.first() else {
+        return;
+    };
+
+    let mut min = first;
+    let mut max = first;
+    for &(position, _) in &bodies {
+        min = min.min(position);
+        max = max.max(position);
+    }
+
+    let center = (min + max) * 0.5;
+    let half_size = ((max - min).max_element() * 0.5).max(1.0);
+
+    let mut root = QuadNode::new_leaf(center, half_size);
+    for &(position, mass) in &bodies {
+        root.insert(position, mass);
+    }
+
+    for (transform, mut particle) in &mut query {
+        let acceleration = root.acceleration_at(transform.translation.xy(), &gravity);
+        particle.velocity += acceleration * delta_time;
     }
 }
 
+// Boids-style flocking, using the Grid's home-cell buckets as the neighbor broadphase instead
+// of an O(n^2) scan. Toggle via `Flocking::enabled` to run this instead of the MPM solve.
+fn apply_flocking(
+    grid: Res<Grid>,
+    flocking: Res<Flocking>,
+    time: Res<Time>,
+    mut query: Query<(Entity, &mut Transform, &mut Particle)>,
+) {
+    if !flocking.enabled {
+        return;
+    }
+
+    let delta_time = time.delta_secs();
+
+    let mut positions = HashMap::new();
+    let mut velocities = HashMap::new();
+    for (entity, transform, particle) in &query {
+        positions.insert(entity, transform.translation.xy());
+        velocities.insert(entity, particle.velocity);
+    }
 
+    for (entity, mut transform, mut particle) in &mut query {
+        let position = transform.translation.xy();
+        let cell_idx = grid.world_to_cell(position);
+
+        let mut average_velocity = Vec2::ZERO;
+        let mut average_position = Vec2::ZERO;
+        let mut separation = Vec2::ZERO;
+        let mut neighbor_count = 0;
+
+        for gx in -1..=1 {
+            for gy in -1..=1 {
+                let Some(cell) = grid.cells.get(&(cell_idx.0 + gx, cell_idx.1 + gy)) else {
+                    continue;
+                };
+
+                for &other in &cell.entities {
+                    if other == entity {
+                        continue;
+                    }
+
+                    let offset = positions[&other] - position;
+                    let distance = offset.length();
+                    if distance > flocking.perception_radius || distance <= f32::EPSILON {
+                        continue;
+                    }
+
+                    average_velocity += velocities[&other];
+                    average_position += positions[&other];
+                    neighbor_count += 1;
+
+                    if distance < flocking.separation_radius {
+                        separation -= offset / (distance * distance); // weighted by inverse distance
+                    }
+                }
+            }
+        }
+
+        if neighbor_count > 0 {
+            let neighbor_count = neighbor_count as f32;
+            let alignment = average_velocity / neighbor_count - particle.velocity;
+            let cohesion = average_position / neighbor_count - position;
+
+            let steering = alignment * flocking.alignment_weight
+                + cohesion * flocking.cohesion_weight
+                + separation * flocking.separation_weight;
+
+            particle.velocity += steering * delta_time;
+        }
 
+        transform.translation += (delta_time * particle.velocity).extend(0.0);
+    }
+}
 
 // Particle Collision Handling
+//
+// Broadphase is a uniform spatial hash (cell size = the largest particle diameter), built fresh
+// each frame and binned with the same `world_to_cell` helper the MPM grid uses. Each particle
+// only tests the 3x3 block of bins around it instead of every other particle.
 fn resolve_collisions(
     mut query: Query<(Entity, &mut Particle, &mut Transform, &Sprite)>,
 ) {
-    let mut checked_pairs = std::collections::HashSet::<(u32, u32)>::new();
-    let mut iter = query.iter_combinations_mut();
+    let entities: Vec<(Entity, Vec2)> = query
+        .iter()
+        .map(|(entity, _, transform, _)| (entity, transform.translation.xy()))
+        .collect();
+
+    let cell_size = query
+        .iter()
+        .map(|(_, _, _, sprite)| sprite.custom_size.unwrap_or(Vec2::splat(5.0)).x)
+        .fold(5.0_f32, f32::max);
+
+    let mut bins: HashMap<(i32, i32), Vec<Entity>> = HashMap::new();
+    for &(entity, position) in &entities {
+        bins.entry(world_to_cell(position, cell_size)).or_default().push(entity);
+    }
 
-    while let Some([
-        (entity_a, mut particle_a, mut transform_a, sprite_a),
-        (entity_b, mut particle_b, mut transform_b, sprite_b)
-    ]) = iter.fetch_next()
-    {
-        let id_a = entity_a.index();
-        let id_b = entity_b.index();
+    let mut checked_pairs = std::collections::HashSet::<(u32, u32)>::new();
 
-        if id_a == id_b || checked_pairs.contains(&(id_b, id_a)) {
-            continue;
+    for &(entity_a, pos_a) in &entities {
+        let cell_idx = world_to_cell(pos_a, cell_size);
+
+        for gx in -1..=1 {
+            for gy in -1..=1 {
+                let Some(bucket) = bins.get(&(cell_idx.0 + gx, cell_idx.1 + gy)) else {
+                    continue;
+                };
+
+                for &entity_b in bucket {
+                    if entity_a == entity_b {
+                        continue;
+                    }
+
+                    let id_a = entity_a.index();
+                    let id_b = entity_b.index();
+                    let pair = (id_a.min(id_b), id_a.max(id_b));
+                    if !checked_pairs.insert(pair) {
+                        continue;
+                    }
+
+                    let Ok([(mut particle_a, mut transform_a, sprite_a), (mut particle_b, mut transform_b, sprite_b)]) =
+                        query.get_many_mut([entity_a, entity_b])
+                    else {
+                        continue;
+                    };
+
+                    let pos_a = transform_a.translation.xy();
+                    let pos_b = transform_b.translation.xy();
+                    let radius_a = sprite_a.custom_size.unwrap_or(Vec2::splat(5.0)).x / 2.0;
+                    let radius_b = sprite_b.custom_size.unwrap_or(Vec2::splat(5.0)).x / 2.0;
+
+                    let diff = pos_b - pos_a;
+                    let distance = diff.length();
+                    let min_distance = radius_a + radius_b;
+
+                    if distance < min_distance {
+                        let normal = diff.normalize_or_zero();
+                        let penetration = min_distance - distance;
+
+                        // Move particles apart correctly
+                        let correction = normal * (penetration / 2.0);
+                        transform_a.translation -= correction.extend(0.0);
+                        transform_b.translation += correction.extend(0.0);
+
+                        // Proper velocity reflection using momentum conservation
+                        let velocity_a = particle_a.velocity;
+                        let velocity_b = particle_b.velocity;
+
+                        let relative_velocity = velocity_b - velocity_a;
+                        let velocity_along_normal = relative_velocity.dot(normal);
+
+                        if velocity_along_normal > 0.0 {
+                            continue;
+                        }
+
+                        let restitution = 0.8;
+                        let impulse_magnitude = -(1.0 + restitution) * velocity_along_normal / 2.0;
+
+                        let impulse = normal * impulse_magnitude;
+                        particle_a.velocity -= impulse;
+                        particle_b.velocity += impulse;
+                    }
+                }
+            }
         }
+    }
+}
 
-        checked_pairs.insert((id_a, id_b));
-
-        let pos_a = transform_a.translation.xy();
-        let pos_b = transform_b.translation.xy();
-        let radius_a = sprite_a.custom_size.unwrap_or(Vec2::splat(5.0)).x / 2.0;
-        let radius_b = sprite_b.custom_size.unwrap_or(Vec2::splat(5.0)).x / 2.0;
-
-        let diff = pos_b - pos_a;
-        let distance = diff.length();
-        let min_distance = radius_a + radius_b;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if distance < min_distance {
-            let normal = diff.normalize_or_zero();
-            let penetration = min_distance - distance;
+    fn build_tree(bodies: &[(Vec2, f32)]) -> QuadNode {
+        let mut min = bodies[0].0;
+        let mut max = bodies[0].0;
+        for &(position, _) in bodies {
+            min = min.min(position);
+            max = max.max(position);
+        }
 
-            // Move particles apart correctly
-            let correction = normal * (penetration / 2.0);
-            transform_a.translation -= correction.extend(0.0);
-            transform_b.translation += correction.extend(0.0);
+        let center = (min + max) * 0.5;
+        let half_size = ((max - min).max_element() * 0.5).max(1.0);
 
-            // Proper velocity reflection using momentum conservation
-            let velocity_a = particle_a.velocity;
-            let velocity_b = particle_b.velocity;
+        let mut root = QuadNode::new_leaf(center, half_size);
+        for &(position, mass) in bodies {
+            root.insert(position, mass);
+        }
+        root
+    }
 
-            let relative_velocity = velocity_b - velocity_a;
-            let velocity_along_normal = relative_velocity.dot(normal);
+    fn brute_force_acceleration(bodies: &[(Vec2, f32)], index: usize, gravity: &NBodyGravity) -> Vec2 {
+        let (position, _) = bodies[index];
+        let mut acceleration = Vec2::ZERO;
 
-            if velocity_along_normal > 0.0 {
+        for (j, &(other_position, other_mass)) in bodies.iter().enumerate() {
+            if j == index {
                 continue;
             }
 
-            let restitution = 0.8;
-            let impulse_magnitude = -(1.0 + restitution) * velocity_along_normal / 2.0;
+            let offset = other_position - position;
+            let denom = (offset.length_squared() + gravity.softening * gravity.softening).powf(1.5);
+            acceleration += gravity.g * other_mass * offset / denom;
+        }
+
+        acceleration
+    }
+
+    #[test]
+    fn quadtree_matches_brute_force_when_theta_is_zero() {
+        // theta = 0 forces the tree to descend all the way to individual bodies, so the
+        // result should be an exact (up to float error) pairwise Newtonian sum.
+        let gravity = NBodyGravity { g: 50.0, softening: 2.0, theta: 0.0 };
+        let bodies = [
+            (Vec2::new(-10.0, 5.0), 2.0),
+            (Vec2::new(8.0, -3.0), 1.5),
+            (Vec2::new(2.0, 12.0), 3.0),
+        ];
+
+        let tree = build_tree(&bodies);
 
-            let impulse = normal * impulse_magnitude;
-            particle_a.velocity -= impulse;
-            particle_b.velocity += impulse;
+        for (i, &(position, _)) in bodies.iter().enumerate() {
+            let expected = brute_force_acceleration(&bodies, i, &gravity);
+            let actual = tree.acceleration_at(position, &gravity);
+            assert!(
+                (actual - expected).length() < 1e-3,
+                "body {i}: expected {expected:?}, got {actual:?}"
+            );
         }
     }
+
+    #[test]
+    fn quadtree_far_field_approximation_matches_combined_point_mass() {
+        // Two close bodies queried from far away, with theta large enough that the tree treats
+        // them as one node, should match a single point mass at their combined center of mass.
+        let gravity = NBodyGravity { g: 50.0, softening: 1.0, theta: 10.0 };
+        let bodies = [(Vec2::new(0.0, 0.0), 2.0), (Vec2::new(1.0, 0.0), 2.0)];
+
+        let tree = build_tree(&bodies);
+
+        let query_position = Vec2::new(1000.0, 0.0);
+        let actual = tree.acceleration_at(query_position, &gravity);
+
+        let total_mass = 4.0;
+        let center_of_mass = Vec2::new(0.5, 0.0);
+        let offset = center_of_mass - query_position;
+        let denom = (offset.length_squared() + gravity.softening * gravity.softening).powf(1.5);
+        let expected = gravity.g * total_mass * offset / denom;
+
+        assert!((actual - expected).length() < 1e-3, "expected {expected:?}, got {actual:?}");
+    }
 }